@@ -1,8 +1,10 @@
+use std::thread;
+
 use tensorflow::{
-    Graph, ImportGraphDefOptions, Session, SessionOptions, SessionRunArgs, Status, Tensor,
+    Code, Graph, ImportGraphDefOptions, Session, SessionOptions, SessionRunArgs, Status, Tensor,
 };
 
-use image::{DynamicImage, GenericImageView, Rgba};
+use image::{imageops::FilterType, DynamicImage, GenericImage, GenericImageView, Rgba};
 use imageproc::drawing::draw_hollow_rect_mut;
 use imageproc::rect::Rect;
 
@@ -10,12 +12,19 @@ use log::debug;
 
 use serde_derive::Serialize;
 
+mod detector;
+pub use detector::{Detector, GpuRuntime, OnnxDetector, OnnxDetectorError, TensorData, TensorMap};
+
+mod evaluate;
+pub use evaluate::{evaluate, EvaluationResult, Sample};
+
 pub struct Mtcnn {
     graph: Graph,
     session: Session,
     min_size: Tensor<f32>,
     thresholds: Tensor<f32>,
     factor: Tensor<f32>,
+    nms: Option<NmsConfig>,
 }
 
 #[derive(Copy, Clone, Debug, Serialize)]
@@ -26,6 +35,8 @@ pub struct BBox {
     pub x2: f32,
     pub y2: f32,
     pub prob: f32,
+    // Left eye, right eye, nose, left mouth corner, right mouth corner, in that order.
+    pub landmarks: [(f32, f32); 5],
 }
 
 // The line colour never changes, so make it a `const`
@@ -33,6 +44,117 @@ const LINE_COLOUR: Rgba<u8> = Rgba {
     data: [0, 255, 0, 0],
 };
 
+// How IoU is measured when comparing two candidate boxes during NMS.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NmsMode {
+    // `inter / (areaA + areaB - inter)`, the usual definition.
+    Union,
+    // `inter / min(areaA, areaB)`, used by MTCNN between its own pyramid stages.
+    Min,
+}
+
+// Parameters for the NMS pass that `Mtcnn::with_nms` applies automatically after `run`.
+#[derive(Copy, Clone, Debug)]
+struct NmsConfig {
+    iou_threshold: f32,
+    score_threshold: f32,
+    keep_top_k: usize,
+    mode: NmsMode,
+}
+
+// Decode the raw `box`/`prob`/`landmarks` tensors MTCNN's O-Net stage produces into `BBox`es.
+// Shared by `Mtcnn::run` and any other `Detector` backend decoding the same graph outputs, so
+// the two can't drift apart.
+//
+// `bbox_res` holds 4 floats per face (`y1, x1, y2, x2`), `prob_res` holds 1 float per face, and
+// `landmarks_res` holds 10 floats per face: 5 x coordinates followed by 5 y coordinates.
+pub(crate) fn decode_outputs(bbox_res: &[f32], prob_res: &[f32], landmarks_res: &[f32]) -> Vec<BBox> {
+    let mut bboxes = Vec::new();
+
+    let mut i = 0;
+    let mut j = 0;
+    let mut k = 0;
+
+    //While we have responses, iterate through
+    while i < bbox_res.len() {
+        //Pull out this face's 10 landmark floats and pair the x's up with the y's.
+        let mut landmark_points = [(0f32, 0f32); 5];
+        for (point, pair) in landmark_points.iter_mut().enumerate() {
+            *pair = (landmarks_res[k + point], landmarks_res[k + 5 + point]);
+        }
+
+        //Add in the 4 floats from the `bbox_res` array.
+        //Notice the y1, x1, etc.. is ordered differently to our struct definition.
+        bboxes.push(BBox {
+            y1: bbox_res[i],
+            x1: bbox_res[i + 1],
+            y2: bbox_res[i + 2],
+            x2: bbox_res[i + 3],
+            prob: prob_res[j], // Add in the facial probability
+            landmarks: landmark_points,
+        });
+
+        //Step `i` ahead by 4.
+        i += 4;
+        //Step `j` ahead by 1.
+        j += 1;
+        //Step `k` ahead by 10.
+        k += 10;
+    }
+
+    bboxes
+}
+
+fn bbox_area(bbox: &BBox) -> f32 {
+    (bbox.x2 - bbox.x1).max(0.0) * (bbox.y2 - bbox.y1).max(0.0)
+}
+
+fn iou(a: &BBox, b: &BBox, mode: NmsMode) -> f32 {
+    let inter_w = (a.x2.min(b.x2) - a.x1.max(b.x1)).max(0.0);
+    let inter_h = (a.y2.min(b.y2) - a.y1.max(b.y1)).max(0.0);
+    let inter = inter_w * inter_h;
+
+    let denom = match mode {
+        NmsMode::Union => bbox_area(a) + bbox_area(b) - inter,
+        NmsMode::Min => bbox_area(a).min(bbox_area(b)),
+    };
+
+    //Guard against zero-area boxes.
+    if denom <= 0.0 {
+        0.0
+    } else {
+        inter / denom
+    }
+}
+
+// Greedily dedupe overlapping boxes in place: drop anything below `score_threshold`, then
+// repeatedly keep the highest-probability survivor and discard candidates that overlap it
+// by more than `iou_threshold`, until `keep_top_k` boxes have been kept.
+pub fn nms(
+    bboxes: &mut Vec<BBox>,
+    iou_threshold: f32,
+    score_threshold: f32,
+    keep_top_k: usize,
+    mode: NmsMode,
+) {
+    let mut candidates: Vec<BBox> = bboxes
+        .drain(..)
+        .filter(|bbox| bbox.prob >= score_threshold)
+        .collect();
+
+    candidates.sort_by(|a, b| b.prob.total_cmp(&a.prob));
+
+    let mut kept = Vec::new();
+
+    while !candidates.is_empty() && kept.len() < keep_top_k {
+        let best = candidates.remove(0);
+        candidates.retain(|candidate| iou(&best, candidate, mode) <= iou_threshold);
+        kept.push(best);
+    }
+
+    *bboxes = kept;
+}
+
 pub fn overlay(img: &DynamicImage, bboxes: &Vec<BBox>) -> DynamicImage {
     let mut output_image = img.clone();
 
@@ -49,8 +171,277 @@ pub fn overlay(img: &DynamicImage, bboxes: &Vec<BBox>) -> DynamicImage {
     output_image
 }
 
+// Canonical 5-point template for a unit-square face chip, in the same left eye/right eye/nose/
+// left mouth corner/right mouth corner order as `BBox::landmarks`. Scaled up to the requested
+// output size before solving the alignment transform.
+const CANONICAL_LANDMARKS: [(f32, f32); 5] = [
+    (0.3461, 0.4614),
+    (0.6538, 0.4614),
+    (0.5000, 0.6254),
+    (0.3730, 0.7903),
+    (0.6317, 0.7903),
+];
+
+// Crop each detected face out of `img` into its own `size x size` chip. With `align` set, the
+// crop is skipped in favour of warping the face onto `CANONICAL_LANDMARKS` using the detected
+// landmarks, producing a chip suitable for feeding a recognition/embedding model.
+pub fn crop_faces(img: &DynamicImage, bboxes: &[BBox], size: u32, align: bool) -> Vec<DynamicImage> {
+    bboxes
+        .iter()
+        .map(|bbox| {
+            if align {
+                align_face(img, bbox, size)
+            } else {
+                crop_and_resize(img, bbox, size)
+            }
+        })
+        .collect()
+}
+
+fn crop_and_resize(img: &DynamicImage, bbox: &BBox, size: u32) -> DynamicImage {
+    let x = bbox.x1.max(0.0) as u32;
+    let y = bbox.y1.max(0.0) as u32;
+    let width = (bbox.x2 - bbox.x1).max(1.0) as u32;
+    let height = (bbox.y2 - bbox.y1).max(1.0) as u32;
+
+    img.clone()
+        .crop(x, y, width, height)
+        .resize_exact(size, size, FilterType::Triangle)
+}
+
+fn align_face(img: &DynamicImage, bbox: &BBox, size: u32) -> DynamicImage {
+    let mut template = [(0f32, 0f32); 5];
+    for (point, canonical) in template.iter_mut().zip(CANONICAL_LANDMARKS.iter()) {
+        *point = (canonical.0 * size as f32, canonical.1 * size as f32);
+    }
+
+    let (rotation, scale, translation) = similarity_transform(&bbox.landmarks, &template);
+
+    let mut output = DynamicImage::new_rgba8(size, size);
+
+    //Walk the destination chip and pull each pixel back through the inverse transform: the
+    //solved transform maps detected landmarks onto the template, not the other way around.
+    for out_y in 0..size {
+        for out_x in 0..size {
+            let dx = out_x as f32 - translation.0;
+            let dy = out_y as f32 - translation.1;
+
+            //`rotation` is orthogonal, so its inverse is its transpose.
+            let src_x = (rotation[0][0] * dx + rotation[1][0] * dy) / scale;
+            let src_y = (rotation[0][1] * dx + rotation[1][1] * dy) / scale;
+
+            output.put_pixel(out_x, out_y, sample_bilinear(img, src_x, src_y));
+        }
+    }
+
+    output
+}
+
+fn sample_bilinear(img: &DynamicImage, x: f32, y: f32) -> Rgba<u8> {
+    let (width, height) = img.dimensions();
+
+    if x < 0.0 || y < 0.0 || x >= (width - 1) as f32 || y >= (height - 1) as f32 {
+        return Rgba { data: [0, 0, 0, 0] };
+    }
+
+    let x0 = x.floor() as u32;
+    let y0 = y.floor() as u32;
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let p00 = img.get_pixel(x0, y0);
+    let p10 = img.get_pixel(x0 + 1, y0);
+    let p01 = img.get_pixel(x0, y0 + 1);
+    let p11 = img.get_pixel(x0 + 1, y0 + 1);
+
+    let mut data = [0u8; 4];
+    for c in 0..4 {
+        let top = p00[c] as f32 * (1.0 - fx) + p10[c] as f32 * fx;
+        let bottom = p01[c] as f32 * (1.0 - fx) + p11[c] as f32 * fx;
+        data[c] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+
+    Rgba { data }
+}
+
+fn mean_point(points: &[(f32, f32); 5]) -> (f32, f32) {
+    let n = points.len() as f32;
+    let sum = points
+        .iter()
+        .fold((0f32, 0f32), |acc, p| (acc.0 + p.0, acc.1 + p.1));
+
+    (sum.0 / n, sum.1 / n)
+}
+
+// Umeyama's method: solve for the rotation, uniform scale and translation that best map `src`
+// onto `dst` in a least-squares sense.
+fn similarity_transform(
+    src: &[(f32, f32); 5],
+    dst: &[(f32, f32); 5],
+) -> ([[f32; 2]; 2], f32, (f32, f32)) {
+    let n = src.len() as f32;
+
+    let src_mean = mean_point(src);
+    let dst_mean = mean_point(dst);
+
+    //2x2 covariance matrix between the centered destination and source points, and the
+    //variance of the (centered) source points.
+    let mut cov = [[0f32; 2]; 2];
+    let mut src_var = 0f32;
+
+    for i in 0..src.len() {
+        let sx = src[i].0 - src_mean.0;
+        let sy = src[i].1 - src_mean.1;
+        let dx = dst[i].0 - dst_mean.0;
+        let dy = dst[i].1 - dst_mean.1;
+
+        cov[0][0] += dx * sx;
+        cov[0][1] += dx * sy;
+        cov[1][0] += dy * sx;
+        cov[1][1] += dy * sy;
+
+        src_var += sx * sx + sy * sy;
+    }
+
+    for row in cov.iter_mut() {
+        for value in row.iter_mut() {
+            *value /= n;
+        }
+    }
+    src_var /= n;
+
+    let (u, sigma, vt) = svd2x2(cov);
+
+    //Flip the sign of the last singular vector if U * V^T is a reflection rather than a
+    //rotation, per Umeyama.
+    let det_u = u[0][0] * u[1][1] - u[0][1] * u[1][0];
+    let det_vt = vt[0][0] * vt[1][1] - vt[0][1] * vt[1][0];
+    let s = if det_u * det_vt < 0.0 {
+        [1f32, -1f32]
+    } else {
+        [1f32, 1f32]
+    };
+
+    let mut rotation = [[0f32; 2]; 2];
+    for i in 0..2 {
+        for j in 0..2 {
+            rotation[i][j] = s[0] * u[i][0] * vt[0][j] + s[1] * u[i][1] * vt[1][j];
+        }
+    }
+
+    let trace_sigma = sigma[0] * s[0] + sigma[1] * s[1];
+    let scale = if src_var > 0.0 {
+        trace_sigma / src_var
+    } else {
+        1.0
+    };
+
+    let translation = (
+        dst_mean.0 - scale * (rotation[0][0] * src_mean.0 + rotation[0][1] * src_mean.1),
+        dst_mean.1 - scale * (rotation[1][0] * src_mean.0 + rotation[1][1] * src_mean.1),
+    );
+
+    (rotation, scale, translation)
+}
+
+// Closed-form SVD of a 2x2 matrix `m = u * diag(sigma) * vt`, following the standard
+// Jacobi-style construction (see e.g. Blinn, "Consequences of Pythagoras").
+fn svd2x2(m: [[f32; 2]; 2]) -> ([[f32; 2]; 2], [f32; 2], [[f32; 2]; 2]) {
+    let (a, b, c, d) = (m[0][0], m[0][1], m[1][0], m[1][1]);
+
+    let e = (a + d) / 2.0;
+    let f = (a - d) / 2.0;
+    let g = (c + b) / 2.0;
+    let h = (c - b) / 2.0;
+
+    let q = (e * e + h * h).sqrt();
+    let r = (f * f + g * g).sqrt();
+
+    let a1 = g.atan2(f);
+    let a2 = h.atan2(e);
+
+    let theta = (a2 - a1) / 2.0;
+    let phi = (a2 + a1) / 2.0;
+
+    let u = [[phi.cos(), -phi.sin()], [phi.sin(), phi.cos()]];
+    //Blinn's construction already yields `rot(theta)` as V^T, not V, so return it as-is.
+    let vt = [[theta.cos(), -theta.sin()], [theta.sin(), theta.cos()]];
+
+    (u, [q + r, q - r], vt)
+}
+
+// Builds an `Mtcnn` with pyramid scaling parameters tuned for the caller's use case, instead
+// of the fixed `min_size`/`thresholds`/`factor` `Mtcnn::new` bakes in.
+pub struct MtcnnBuilder {
+    min_face_size: f32,
+    thresholds: [f32; 3],
+    scale_factor: f32,
+}
+
+impl Default for MtcnnBuilder {
+    fn default() -> Self {
+        Self {
+            min_face_size: 40.0,
+            thresholds: [0.6, 0.7, 0.7],
+            scale_factor: 0.709,
+        }
+    }
+}
+
+impl MtcnnBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Smallest face, in pixels, that the first pyramid stage (P-Net) will look for.
+    pub fn min_face_size(mut self, min_face_size: f32) -> Self {
+        self.min_face_size = min_face_size;
+        self
+    }
+
+    // Per-stage (P-Net/R-Net/O-Net) confidence thresholds.
+    pub fn thresholds(mut self, thresholds: [f32; 3]) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    // How much the image pyramid shrinks between scales.
+    pub fn scale_factor(mut self, scale_factor: f32) -> Self {
+        self.scale_factor = scale_factor;
+        self
+    }
+
+    pub fn build(self) -> Result<Mtcnn, Status> {
+        for threshold in &self.thresholds {
+            if *threshold <= 0.0 || *threshold >= 1.0 {
+                return Err(invalid_argument(format!(
+                    "threshold {} must be in (0, 1)",
+                    threshold
+                )));
+            }
+        }
+
+        if self.scale_factor <= 0.0 || self.scale_factor >= 1.0 {
+            return Err(invalid_argument(format!(
+                "scale factor {} must be in (0, 1)",
+                self.scale_factor
+            )));
+        }
+
+        Mtcnn::from_config(self.min_face_size, self.thresholds, self.scale_factor)
+    }
+}
+
+fn invalid_argument(msg: String) -> Status {
+    Status::new_set(Code::InvalidArgument, &msg)
+        .expect("building an InvalidArgument status should not fail")
+}
+
 impl Mtcnn {
     pub fn new() -> Result<Self, Status> {
+        MtcnnBuilder::new().build()
+    }
+
+    fn from_config(min_face_size: f32, thresholds: [f32; 3], scale_factor: f32) -> Result<Self, Status> {
         //First, we load up the graph as a byte array
         let model = include_bytes!("mtcnn.pb");
 
@@ -61,10 +452,10 @@ impl Mtcnn {
         //Create a session to reuse
         let session = Session::new(&SessionOptions::new(), &graph)?;
 
-        //Use input params from the existing module
-        let min_size = Tensor::new(&[]).with_values(&[40f32])?;
-        let thresholds = Tensor::new(&[3]).with_values(&[0.6f32, 0.7f32, 0.7f32])?;
-        let factor = Tensor::new(&[]).with_values(&[0.709f32])?;
+        //Use the parameters the builder was configured with
+        let min_size = Tensor::new(&[]).with_values(&[min_face_size])?;
+        let thresholds = Tensor::new(&[3]).with_values(&thresholds)?;
+        let factor = Tensor::new(&[]).with_values(&[scale_factor])?;
 
         Ok(Self {
             graph,
@@ -72,9 +463,28 @@ impl Mtcnn {
             min_size,
             thresholds,
             factor,
+            nms: None,
         })
     }
 
+    // Apply NMS to the boxes returned from `run`, using the given thresholds and `mode`.
+    pub fn with_nms(
+        mut self,
+        iou_threshold: f32,
+        score_threshold: f32,
+        keep_top_k: usize,
+        mode: NmsMode,
+    ) -> Self {
+        self.nms = Some(NmsConfig {
+            iou_threshold,
+            score_threshold,
+            keep_top_k,
+            mode,
+        });
+
+        self
+    }
+
     pub fn run(&self, img: &DynamicImage) -> Result<Vec<BBox>, Status> {
         //Create `flattened` BGR data for the `input`
         let input = {
@@ -114,6 +524,8 @@ impl Mtcnn {
         //Request the following outputs after the session runs
         let bbox = args.request_fetch(&self.graph.operation_by_name_required("box")?, 0);
         let prob = args.request_fetch(&self.graph.operation_by_name_required("prob")?, 0);
+        let landmarks =
+            args.request_fetch(&self.graph.operation_by_name_required("landmarks")?, 0);
 
         //Run the session
         &self.session.run(&mut args)?;
@@ -122,33 +534,119 @@ impl Mtcnn {
         let bbox_res: Tensor<f32> = args.fetch(bbox)?;
         //Our facial probability
         let prob_res: Tensor<f32> = args.fetch(prob)?;
+        //Our five facial landmark points, 10 floats per face: 5 x coordinates followed by 5 y coordinates
+        let landmarks_res: Tensor<f32> = args.fetch(landmarks)?;
 
         //Let's store the results as a Vec<BBox>
-        let mut bboxes = Vec::new();
-
-        let mut i = 0;
-        let mut j = 0;
-
-        //While we have responses, iterate through
-        while i < bbox_res.len() {
-            //Add in the 4 floats from the `bbox_res` array.
-            //Notice the y1, x1, etc.. is ordered differently to our struct definition.
-            bboxes.push(BBox {
-                y1: bbox_res[i],
-                x1: bbox_res[i + 1],
-                y2: bbox_res[i + 2],
-                x2: bbox_res[i + 3],
-                prob: prob_res[j], // Add in the facial probability
-            });
-
-            //Step `i` ahead by 4.
-            i += 4;
-            //Step `i` ahead by 1.
-            j += 1;
+        let mut bboxes = decode_outputs(&bbox_res, &prob_res, &landmarks_res);
+
+        //If the caller configured `with_nms`, dedupe overlapping boxes before returning.
+        if let Some(config) = &self.nms {
+            nms(
+                &mut bboxes,
+                config.iou_threshold,
+                config.score_threshold,
+                config.keep_top_k,
+                config.mode,
+            );
         }
 
         debug!("BBox Length: {}, BBoxes:{:#?}", bboxes.len(), bboxes);
 
         Ok(bboxes)
     }
+
+    // Detect faces across several images, reusing this `Mtcnn`'s session instead of creating a
+    // new one per frame. MTCNN's pyramid scaling makes each stage's tensor shapes depend on the
+    // input's resolution, so frames can't be packed into a single batched session run; instead
+    // each image is run on its own scoped thread against the shared session, and `result[i]`
+    // holds `imgs[i]`'s detections.
+    pub fn run_batch(&self, imgs: &[DynamicImage]) -> Result<Vec<Vec<BBox>>, Status> {
+        thread::scope(|scope| {
+            let handles: Vec<_> = imgs
+                .iter()
+                .map(|img| scope.spawn(move || self.run(img)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("face-detection thread panicked"))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbox(x1: f32, y1: f32, x2: f32, y2: f32, prob: f32) -> BBox {
+        BBox {
+            x1,
+            y1,
+            x2,
+            y2,
+            prob,
+            landmarks: [(0.0, 0.0); 5],
+        }
+    }
+
+    #[test]
+    fn iou_matches_hand_computed_value() {
+        //Two unit squares overlapping in a 0.5 x 1.0 strip: intersection 0.5, union 1.5.
+        let a = bbox(0.0, 0.0, 1.0, 1.0, 1.0);
+        let b = bbox(0.5, 0.0, 1.5, 1.0, 1.0);
+
+        assert!((iou(&a, &b, NmsMode::Union) - (0.5 / 1.5)).abs() < 1e-6);
+        assert!((iou(&a, &b, NmsMode::Min) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn nms_keeps_only_top_k_after_suppressing_overlaps() {
+        let mut boxes = vec![
+            bbox(0.0, 0.0, 10.0, 10.0, 0.95),
+            bbox(1.0, 1.0, 11.0, 11.0, 0.9), // heavily overlaps the first, should be suppressed
+            bbox(100.0, 100.0, 110.0, 110.0, 0.8), // disjoint, should survive
+            bbox(200.0, 200.0, 210.0, 210.0, 0.7), // disjoint, but keep_top_k = 2 drops it
+        ];
+
+        nms(&mut boxes, 0.3, 0.5, 2, NmsMode::Union);
+
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(boxes[0].prob, 0.95);
+        assert_eq!(boxes[1].prob, 0.8);
+    }
+
+    #[test]
+    fn similarity_transform_recovers_a_known_rotation_scale_and_translation() {
+        let src = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0), (0.5, 0.5)];
+
+        let angle = 30f32.to_radians();
+        let scale = 2.0f32;
+        let translation = (10.0f32, 5.0f32);
+
+        let mut dst = [(0f32, 0f32); 5];
+        for (slot, &(x, y)) in dst.iter_mut().zip(src.iter()) {
+            let rx = angle.cos() * x - angle.sin() * y;
+            let ry = angle.sin() * x + angle.cos() * y;
+            *slot = (scale * rx + translation.0, scale * ry + translation.1);
+        }
+
+        let (rotation, recovered_scale, recovered_translation) = similarity_transform(&src, &dst);
+
+        assert!((recovered_scale - scale).abs() < 1e-3);
+        assert!((recovered_translation.0 - translation.0).abs() < 1e-3);
+        assert!((recovered_translation.1 - translation.1).abs() < 1e-3);
+
+        //The recovered transform should map every source point back onto its destination point.
+        for (&(x, y), &(expected_x, expected_y)) in src.iter().zip(dst.iter()) {
+            let got_x =
+                recovered_scale * (rotation[0][0] * x + rotation[0][1] * y) + recovered_translation.0;
+            let got_y =
+                recovered_scale * (rotation[1][0] * x + rotation[1][1] * y) + recovered_translation.1;
+
+            assert!((got_x - expected_x).abs() < 1e-3);
+            assert!((got_y - expected_y).abs() < 1e-3);
+        }
+    }
 }
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use image::{DynamicImage, GenericImageView};
+
+use tensorflow::Status;
+
+use super::{decode_outputs, BBox, Mtcnn};
+
+// One named tensor exchanged with a `Detector` backend's underlying runtime.
+#[derive(Clone, Debug)]
+pub enum TensorData {
+    F32(Vec<f32>),
+    I64(Vec<i64>),
+}
+
+// Ordered map of named tensors, used both to feed a graph's inputs and to read back its
+// fetched outputs.
+pub type TensorMap = BTreeMap<String, TensorData>;
+
+// Common face-detection surface so callers can swap the inference backend without touching
+// the rest of the pipeline.
+pub trait Detector {
+    type Err;
+
+    fn detect(&self, img: &DynamicImage) -> Result<Vec<BBox>, Self::Err>;
+}
+
+// The existing TensorFlow-backed implementation, unchanged from `Mtcnn::run`.
+impl Detector for Mtcnn {
+    type Err = Status;
+
+    fn detect(&self, img: &DynamicImage) -> Result<Vec<BBox>, Self::Err> {
+        self.run(img)
+    }
+}
+
+// An inference backend able to run a named-tensor graph: feed it `inputs`, get back its
+// outputs as another `TensorMap` keyed by output name. `OnnxDetector` is runtime-agnostic and ships with no
+// implementation of this trait — a pure-Rust, WebGPU-style runtime (e.g. `wonnx`) or a CPU
+// runtime (e.g. `tract`) would implement it to load and run an actual MTCNN ONNX model. Until
+// a caller supplies one, there is no working ONNX backend, only this extension point.
+pub trait GpuRuntime {
+    fn run(&self, inputs: TensorMap) -> Result<TensorMap, Box<dyn Error>>;
+}
+
+#[derive(Debug)]
+pub struct OnnxDetectorError(String);
+
+impl fmt::Display for OnnxDetectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "onnx detector error: {}", self.0)
+    }
+}
+
+impl Error for OnnxDetectorError {}
+
+// Decodes an MTCNN ONNX model's `box`/`prob`/`landmarks` outputs the same way `Mtcnn::run`
+// decodes the TensorFlow graph's, but delegates actually running the model to `R`. No
+// `GpuRuntime` ships in this crate, so `OnnxDetector` cannot detect anything on its own —
+// construct it with a runtime that loads the ONNX model and implements `GpuRuntime::run`.
+pub struct OnnxDetector<R: GpuRuntime> {
+    runtime: R,
+}
+
+impl<R: GpuRuntime> OnnxDetector<R> {
+    pub fn new(runtime: R) -> Self {
+        Self { runtime }
+    }
+}
+
+impl<R: GpuRuntime> Detector for OnnxDetector<R> {
+    type Err = Box<dyn Error>;
+
+    fn detect(&self, img: &DynamicImage) -> Result<Vec<BBox>, Self::Err> {
+        //Create `flattened` BGR data for the `input`, same layout as `Mtcnn::run`.
+        let mut flattened: Vec<f32> = Vec::new();
+
+        for (_x, _y, rgb) in img.pixels() {
+            flattened.push(rgb[2] as f32);
+            flattened.push(rgb[1] as f32);
+            flattened.push(rgb[0] as f32);
+        }
+
+        let mut inputs = TensorMap::new();
+        inputs.insert("input".to_string(), TensorData::F32(flattened));
+
+        let outputs = self.runtime.run(inputs)?;
+
+        let bbox_res = f32_output(&outputs, "box")?;
+        let prob_res = f32_output(&outputs, "prob")?;
+        let landmarks_res = f32_output(&outputs, "landmarks")?;
+
+        Ok(decode_outputs(bbox_res, prob_res, landmarks_res))
+    }
+}
+
+fn f32_output<'a>(outputs: &'a TensorMap, name: &str) -> Result<&'a Vec<f32>, OnnxDetectorError> {
+    match outputs.get(name) {
+        Some(TensorData::F32(values)) => Ok(values),
+        Some(TensorData::I64(_)) => Err(OnnxDetectorError(format!(
+            "output `{}` was i64, expected f32",
+            name
+        ))),
+        None => Err(OnnxDetectorError(format!("missing output `{}`", name))),
+    }
+}
@@ -0,0 +1,149 @@
+use super::BBox;
+
+// One image's worth of predictions and ground-truth boxes to score with `evaluate`.
+pub struct Sample {
+    pub predictions: Vec<BBox>,
+    // Ground-truth face boxes as `(x1, y1, x2, y2)`, since they carry no confidence score.
+    pub ground_truth: Vec<(f32, f32, f32, f32)>,
+}
+
+// Counts and precision/recall curve produced by `evaluate`.
+pub struct EvaluationResult {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    // Precision/recall pairs, one per prediction, in descending-score order.
+    pub curve: Vec<(f32, f32)>,
+    pub average_precision: f32,
+}
+
+// IoU-matched precision/recall evaluation, modelled on `detection_map`-style ops: predictions
+// across all `samples` are pooled and sorted by descending score, each is greedily matched to
+// the best unused ground-truth box in its own image whose IoU clears `overlap_threshold`, and
+// the resulting precision/recall curve is integrated into an average precision score.
+pub fn evaluate(samples: &[Sample], overlap_threshold: f32) -> EvaluationResult {
+    let total_gt: usize = samples.iter().map(|sample| sample.ground_truth.len()).sum();
+    let mut matched_gt: Vec<Vec<bool>> = samples
+        .iter()
+        .map(|sample| vec![false; sample.ground_truth.len()])
+        .collect();
+
+    let mut all_predictions: Vec<(usize, BBox)> = Vec::new();
+    for (image_idx, sample) in samples.iter().enumerate() {
+        for bbox in &sample.predictions {
+            all_predictions.push((image_idx, *bbox));
+        }
+    }
+
+    all_predictions.sort_by(|a, b| b.1.prob.total_cmp(&a.1.prob));
+
+    let mut true_positives = 0usize;
+    let mut false_positives = 0usize;
+    let mut curve = Vec::with_capacity(all_predictions.len());
+
+    for (image_idx, bbox) in &all_predictions {
+        let ground_truth = &samples[*image_idx].ground_truth;
+        let gt_matched = &mut matched_gt[*image_idx];
+
+        //Find the best-overlapping, not-yet-matched ground-truth box in this prediction's image.
+        let mut best_iou = 0.0;
+        let mut best_gt = None;
+
+        for (gt_idx, gt) in ground_truth.iter().enumerate() {
+            if gt_matched[gt_idx] {
+                continue;
+            }
+
+            let candidate_iou = rect_iou(bbox, gt);
+            if candidate_iou > best_iou {
+                best_iou = candidate_iou;
+                best_gt = Some(gt_idx);
+            }
+        }
+
+        match best_gt {
+            Some(gt_idx) if best_iou > overlap_threshold => {
+                gt_matched[gt_idx] = true;
+                true_positives += 1;
+            }
+            _ => false_positives += 1,
+        }
+
+        let precision = true_positives as f32 / (true_positives + false_positives) as f32;
+        let recall = if total_gt > 0 {
+            true_positives as f32 / total_gt as f32
+        } else {
+            0.0
+        };
+
+        curve.push((precision, recall));
+    }
+
+    EvaluationResult {
+        true_positives,
+        false_positives,
+        false_negatives: total_gt - true_positives,
+        average_precision: average_precision(&curve),
+        curve,
+    }
+}
+
+fn rect_iou(bbox: &BBox, ground_truth: &(f32, f32, f32, f32)) -> f32 {
+    let (gx1, gy1, gx2, gy2) = *ground_truth;
+
+    let inter_w = (bbox.x2.min(gx2) - bbox.x1.max(gx1)).max(0.0);
+    let inter_h = (bbox.y2.min(gy2) - bbox.y1.max(gy1)).max(0.0);
+    let inter = inter_w * inter_h;
+
+    let area_pred = (bbox.x2 - bbox.x1).max(0.0) * (bbox.y2 - bbox.y1).max(0.0);
+    let area_gt = (gx2 - gx1).max(0.0) * (gy2 - gy1).max(0.0);
+    let denom = area_pred + area_gt - inter;
+
+    //Guard against zero-area boxes.
+    if denom <= 0.0 {
+        0.0
+    } else {
+        inter / denom
+    }
+}
+
+// 11-point interpolated average precision (the VOC2007-style metric): the mean, over recall
+// levels 0.0, 0.1, .., 1.0, of the highest precision observed at or beyond that recall.
+fn average_precision(curve: &[(f32, f32)]) -> f32 {
+    if curve.is_empty() {
+        return 0.0;
+    }
+
+    let mut sum = 0.0;
+
+    for step in 0..=10 {
+        let recall_level = step as f32 / 10.0;
+
+        let max_precision = curve
+            .iter()
+            .filter(|(_, recall)| *recall >= recall_level)
+            .map(|(precision, _)| *precision)
+            .fold(0.0f32, f32::max);
+
+        sum += max_precision;
+    }
+
+    sum / 11.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn average_precision_matches_a_hand_computed_11_point_value() {
+        //A precision/recall curve for 2 true positives out of 2 ground-truth boxes, with one
+        //false positive ahead of the second hit: recall levels 0.0-0.5 see a best precision of
+        //1.0 (6 of the 11 points), recall levels 0.6-1.0 see a best precision of 2/3 (5 points).
+        let curve = vec![(1.0, 0.5), (0.5, 0.5), (2.0 / 3.0, 1.0)];
+
+        let expected = (6.0 * 1.0 + 5.0 * (2.0 / 3.0)) / 11.0;
+
+        assert!((average_precision(&curve) - expected).abs() < 1e-4);
+    }
+}